@@ -1,4 +1,5 @@
 use std::cmp::{Ord, Ordering, PartialOrd};
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 use std::{convert::From, sync::Arc};
@@ -8,7 +9,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::Signature;
 use crate::{hir, Dictionary, PositionalType, Primitive, SyntaxShape, UntaggedValue};
-use crate::{PathMember, ShellTypeName};
+use crate::{PathMember, ShellTypeName, UnspannedPathMember};
 use derive_new::new;
 
 use nu_errors::{ParseError, ShellError};
@@ -23,6 +24,7 @@ use log::trace;
 use num_bigint::{BigInt, ToBigInt};
 use num_traits::identities::Zero;
 use num_traits::FromPrimitive;
+use smallvec::SmallVec;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct InternalCommand {
@@ -50,6 +52,14 @@ impl InternalCommand {
     pub fn get_free_variables(&self, known_variables: &mut Vec<String>) -> Vec<String> {
         self.args.get_free_variables(known_variables)
     }
+
+    pub fn substitute_all(&self, bindings: &HashMap<String, SpannedExpression>) -> InternalCommand {
+        InternalCommand {
+            name: self.name.clone(),
+            name_span: self.name_span,
+            args: self.args.substitute_all(bindings),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
@@ -103,22 +113,56 @@ impl ClassifiedCommand {
             _ => vec![],
         }
     }
+
+    pub fn substitute_all(
+        &self,
+        bindings: &HashMap<String, SpannedExpression>,
+    ) -> ClassifiedCommand {
+        match self {
+            ClassifiedCommand::Expr(expr) => {
+                ClassifiedCommand::Expr(Box::new(expr.substitute_all(bindings)))
+            }
+            ClassifiedCommand::Dynamic(call) => {
+                ClassifiedCommand::Dynamic(call.substitute_all(bindings))
+            }
+            ClassifiedCommand::Internal(internal) => {
+                ClassifiedCommand::Internal(internal.substitute_all(bindings))
+            }
+            ClassifiedCommand::Error(error) => ClassifiedCommand::Error(error.clone()),
+        }
+    }
 }
 
+// `list`/`pipelines`/`block` are almost always one or two elements, so they're
+// backed by an inline-capacity `SmallVec` rather than always heap-allocating
+// like a `Vec` would. `push`/`iter`/`Deref` behave the same either way.
+//
+// NOTE(chunk0-5): the original request asked for benchmarks demonstrating
+// reduced allocation counts. That part of the request is explicitly
+// descoped here, not delivered: this snapshot has no `Cargo.toml`, so
+// there's nowhere to add a `benches/` target, and wiring in a counting
+// global allocator to measure it inline isn't appropriate for a library
+// module — it would affect every downstream consumer of this crate, not
+// just this test. Measuring the allocation-count claim needs a follow-up
+// request filed against a buildable checkout with a real bench harness;
+// until then this is a data-shape argument, not a measured one.
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct Pipeline {
-    pub list: Vec<ClassifiedCommand>,
+    pub list: SmallVec<[ClassifiedCommand; 2]>,
     pub span: Span,
 }
 
 impl Pipeline {
     pub fn new(span: Span) -> Pipeline {
-        Pipeline { list: vec![], span }
+        Pipeline {
+            list: SmallVec::new(),
+            span,
+        }
     }
 
     pub fn basic() -> Pipeline {
         Pipeline {
-            list: vec![],
+            list: SmallVec::new(),
             span: Span::unknown(),
         }
     }
@@ -130,21 +174,35 @@ impl Pipeline {
     pub fn has_var_usage(&self, var_name: &str) -> bool {
         self.list.iter().any(|cc| cc.has_var_usage(var_name))
     }
+
+    pub fn substitute_all(&self, bindings: &HashMap<String, SpannedExpression>) -> Pipeline {
+        Pipeline {
+            list: self
+                .list
+                .iter()
+                .map(|cc| cc.substitute_all(bindings))
+                .collect(),
+            span: self.span,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct Group {
-    pub pipelines: Vec<Pipeline>,
+    pub pipelines: SmallVec<[Pipeline; 2]>,
     pub span: Span,
 }
 impl Group {
-    pub fn new(pipelines: Vec<Pipeline>, span: Span) -> Group {
-        Group { pipelines, span }
+    pub fn new(pipelines: impl Into<SmallVec<[Pipeline; 2]>>, span: Span) -> Group {
+        Group {
+            pipelines: pipelines.into(),
+            span,
+        }
     }
 
     pub fn basic() -> Group {
         Group {
-            pipelines: vec![],
+            pipelines: SmallVec::new(),
             span: Span::unknown(),
         }
     }
@@ -156,6 +214,17 @@ impl Group {
     pub fn has_var_usage(&self, var_name: &str) -> bool {
         self.pipelines.iter().any(|cc| cc.has_var_usage(var_name))
     }
+
+    pub fn substitute_all(&self, bindings: &HashMap<String, SpannedExpression>) -> Group {
+        Group {
+            pipelines: self
+                .pipelines
+                .iter()
+                .map(|pipeline| pipeline.substitute_all(bindings))
+                .collect(),
+            span: self.span,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
@@ -173,7 +242,7 @@ impl CapturedBlock {
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Block {
     pub params: Signature,
-    pub block: Vec<Group>,
+    pub block: SmallVec<[Group; 2]>,
     pub definitions: IndexMap<String, Arc<Block>>,
     pub span: Span,
 }
@@ -181,13 +250,13 @@ pub struct Block {
 impl Block {
     pub fn new(
         params: Signature,
-        block: Vec<Group>,
+        block: impl Into<SmallVec<[Group; 2]>>,
         definitions: IndexMap<String, Arc<Block>>,
         span: Span,
     ) -> Block {
         Block {
             params,
-            block,
+            block: block.into(),
             definitions,
             span,
         }
@@ -196,7 +265,7 @@ impl Block {
     pub fn basic() -> Block {
         Block {
             params: Signature::new("<basic>"),
-            block: vec![],
+            block: SmallVec::new(),
             definitions: IndexMap::new(),
             span: Span::unknown(),
         }
@@ -243,6 +312,35 @@ impl Block {
 
         free_variables
     }
+
+    /// Like [`SpannedExpression::substitute_all`], but first drops any
+    /// binding this block's own parameter list rebinds, so a nested block
+    /// that happens to redeclare a substituted name keeps its own meaning
+    /// (capture avoidance).
+    pub fn substitute_all(&self, bindings: &HashMap<String, SpannedExpression>) -> Block {
+        let mut bindings = bindings.clone();
+        for (positional, _) in &self.params.positional {
+            // The tuple's second element is the parameter's usage
+            // description (see `infer_params`'s `"implied $it"`), not its
+            // bound name — the name lives inside `PositionalType` itself.
+            bindings.remove(positional.name());
+        }
+
+        if bindings.is_empty() {
+            return self.clone();
+        }
+
+        Block {
+            params: self.params.clone(),
+            block: self
+                .block
+                .iter()
+                .map(|group| group.substitute_all(&bindings))
+                .collect(),
+            definitions: self.definitions.clone(),
+            span: self.span,
+        }
+    }
 }
 
 #[allow(clippy::derive_hash_xor_eq)]
@@ -300,7 +398,7 @@ impl std::ops::Deref for ExternalArgs {
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct ExternalArgs {
-    pub list: Vec<SpannedExpression>,
+    pub list: SmallVec<[SpannedExpression; 2]>,
     pub span: Span,
 }
 
@@ -401,7 +499,7 @@ impl HasSpan for Member {
     }
 }
 
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Hash, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Number {
     BigInt(BigInt),
     Int(i64),
@@ -409,6 +507,20 @@ pub enum Number {
 }
 
 impl Number {
+    /// Re-narrow to the smallest exact representation: an in-range `BigInt`
+    /// becomes an `Int`, and a `Decimal` with no fractional part has its
+    /// scale trimmed.
+    pub fn normalize(self) -> Number {
+        match self {
+            Number::BigInt(int) => match int.to_i64() {
+                Some(int) => Number::Int(int),
+                None => Number::BigInt(int),
+            },
+            Number::Int(int) => Number::Int(int),
+            Number::Decimal(decimal) => Number::Decimal(decimal.normalized()),
+        }
+    }
+
     pub fn to_i64(&self) -> Result<i64, ShellError> {
         match self {
             Number::BigInt(bi) => match bi.to_i64() {
@@ -437,6 +549,77 @@ impl Number {
             )),
         }
     }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(i) => *i == 0,
+            Number::BigInt(bi) => bi.is_zero(),
+            Number::Decimal(dec) => dec.is_zero(),
+        }
+    }
+}
+
+// `Number` is a single canonical numeric identity regardless of storage
+// width, so `Int`/`BigInt`/`Decimal` values that denote the same number must
+// compare and hash equally. Hand-written impls replace the derives so that
+// e.g. `Number::Int(5)` and `Number::BigInt(5.into())` are equal.
+impl PartialEq for Number {
+    fn eq(&self, other: &Number) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Number) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Number) -> Ordering {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.cmp(b),
+            (Number::BigInt(a), Number::Int(b)) => a.cmp(&BigInt::from(*b)),
+            (Number::Int(a), Number::BigInt(b)) => BigInt::from(*a).cmp(b),
+            (Number::BigInt(a), Number::BigInt(b)) => a.cmp(b),
+            (Number::Decimal(a), Number::Decimal(b)) => a.cmp(b),
+            (Number::Int(a), Number::Decimal(b)) => BigDecimal::from(*a).cmp(b),
+            (Number::Decimal(a), Number::Int(b)) => a.cmp(&BigDecimal::from(*b)),
+            (Number::BigInt(a), Number::Decimal(b)) => BigDecimal::from(a.clone()).cmp(b),
+            (Number::Decimal(a), Number::BigInt(b)) => a.cmp(&BigDecimal::from(b.clone())),
+        }
+    }
+}
+
+#[allow(clippy::derive_hash_xor_eq)]
+impl Hash for Number {
+    /// Hash the canonical value so that numbers which compare equal also
+    /// hash equally: the normalized `BigInt` when there's no fractional
+    /// part, otherwise the `BigDecimal`'s own representation.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.clone().normalize() {
+            Number::Int(int) => int.hash(state),
+            Number::BigInt(int) => int.hash(state),
+            Number::Decimal(decimal) => {
+                // `normalize()` trims a `Decimal`'s scale but never demotes
+                // a whole-number decimal to `Int`/`BigInt`, so without this
+                // check `Decimal(5.0)` and `Int(5)` — which compare equal —
+                // would hash through completely different paths.
+                let integral = decimal.with_scale(0);
+                if integral == decimal {
+                    let (digits, _) = integral.as_bigint_and_exponent();
+                    match digits.to_i64() {
+                        Some(int) => int.hash(state),
+                        None => digits.hash(state),
+                    }
+                } else {
+                    decimal.to_string().hash(state)
+                }
+            }
+        }
+    }
 }
 
 impl PrettyDebug for Number {
@@ -497,12 +680,59 @@ macro_rules! primitive_decimal {
 
 primitive_decimal!(f32 -> from_f32, f64 -> from_f64);
 
+impl std::ops::Add for Number {
+    type Output = Number;
+
+    fn add(self, other: Number) -> Number {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => match a.checked_add(b) {
+                Some(val) => Number::Int(val),
+                None => Number::BigInt(BigInt::from(a) + BigInt::from(b)),
+            },
+            (Number::BigInt(a), Number::Int(b)) => Number::BigInt(a + BigInt::from(b)),
+            (Number::Int(a), Number::BigInt(b)) => Number::BigInt(BigInt::from(a) + b),
+            (Number::BigInt(a), Number::BigInt(b)) => Number::BigInt(a + b),
+            (Number::Int(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) + b),
+            (Number::Decimal(a), Number::Int(b)) => Number::Decimal(a + BigDecimal::from(b)),
+            (Number::BigInt(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) + b),
+            (Number::Decimal(a), Number::BigInt(b)) => Number::Decimal(a + BigDecimal::from(b)),
+            (Number::Decimal(a), Number::Decimal(b)) => Number::Decimal(a + b),
+        }
+        .normalize()
+    }
+}
+
+impl std::ops::Sub for Number {
+    type Output = Number;
+
+    fn sub(self, other: Number) -> Number {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => match a.checked_sub(b) {
+                Some(val) => Number::Int(val),
+                None => Number::BigInt(BigInt::from(a) - BigInt::from(b)),
+            },
+            (Number::BigInt(a), Number::Int(b)) => Number::BigInt(a - BigInt::from(b)),
+            (Number::Int(a), Number::BigInt(b)) => Number::BigInt(BigInt::from(a) - b),
+            (Number::BigInt(a), Number::BigInt(b)) => Number::BigInt(a - b),
+            (Number::Int(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) - b),
+            (Number::Decimal(a), Number::Int(b)) => Number::Decimal(a - BigDecimal::from(b)),
+            (Number::BigInt(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) - b),
+            (Number::Decimal(a), Number::BigInt(b)) => Number::Decimal(a - BigDecimal::from(b)),
+            (Number::Decimal(a), Number::Decimal(b)) => Number::Decimal(a - b),
+        }
+        .normalize()
+    }
+}
+
 impl std::ops::Mul for Number {
     type Output = Number;
 
     fn mul(self, other: Number) -> Number {
         match (self, other) {
-            (Number::Int(a), Number::Int(b)) => Number::Int(a * b),
+            (Number::Int(a), Number::Int(b)) => match a.checked_mul(b) {
+                Some(val) => Number::Int(val),
+                None => Number::BigInt(BigInt::from(a) * BigInt::from(b)),
+            },
             (Number::BigInt(a), Number::Int(b)) => Number::BigInt(a * BigInt::from(b)),
             (Number::Int(a), Number::BigInt(b)) => Number::BigInt(BigInt::from(a) * b),
             (Number::BigInt(a), Number::BigInt(b)) => Number::BigInt(a * b),
@@ -512,6 +742,7 @@ impl std::ops::Mul for Number {
             (Number::Decimal(a), Number::BigInt(b)) => Number::Decimal(a * BigDecimal::from(b)),
             (Number::Decimal(a), Number::Decimal(b)) => Number::Decimal(a * b),
         }
+        .normalize()
     }
 }
 
@@ -520,11 +751,143 @@ impl std::ops::Mul<u32> for Number {
     type Output = Number;
 
     fn mul(self, other: u32) -> Number {
+        self * Number::from(other)
+    }
+}
+
+impl std::ops::Div for Number {
+    type Output = Result<Number, ShellError>;
+
+    fn div(self, other: Number) -> Result<Number, ShellError> {
+        if other.is_zero() {
+            return Err(ShellError::untagged_runtime_error("Division by zero"));
+        }
+
+        let result = match (self, other) {
+            // `a % b`/`a / b` panic on plain `i64` for `i64::MIN / -1`, so
+            // both go through `checked_*` first and promote to `BigInt` on
+            // `None`, the same overflow-to-`BigInt` pattern `Add`/`Sub`/`Mul`
+            // already use.
+            (Number::Int(a), Number::Int(b)) => match a.checked_rem(b) {
+                Some(0) => match a.checked_div(b) {
+                    Some(quotient) => Number::Int(quotient),
+                    None => Number::BigInt(BigInt::from(a) / BigInt::from(b)),
+                },
+                Some(_) => Number::Decimal(BigDecimal::from(a) / BigDecimal::from(b)),
+                None => Number::BigInt(BigInt::from(a) / BigInt::from(b)),
+            },
+            (Number::BigInt(a), Number::Int(b)) => {
+                let b = BigInt::from(b);
+                if (&a % &b).is_zero() {
+                    Number::BigInt(a / b)
+                } else {
+                    Number::Decimal(BigDecimal::from(a) / BigDecimal::from(b))
+                }
+            }
+            (Number::Int(a), Number::BigInt(b)) => {
+                let a = BigInt::from(a);
+                if (&a % &b).is_zero() {
+                    Number::BigInt(a / b)
+                } else {
+                    Number::Decimal(BigDecimal::from(a) / BigDecimal::from(b))
+                }
+            }
+            (Number::BigInt(a), Number::BigInt(b)) => {
+                if (&a % &b).is_zero() {
+                    Number::BigInt(a / b)
+                } else {
+                    Number::Decimal(BigDecimal::from(a) / BigDecimal::from(b))
+                }
+            }
+            (Number::Int(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) / b),
+            (Number::Decimal(a), Number::Int(b)) => Number::Decimal(a / BigDecimal::from(b)),
+            (Number::BigInt(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) / b),
+            (Number::Decimal(a), Number::BigInt(b)) => Number::Decimal(a / BigDecimal::from(b)),
+            (Number::Decimal(a), Number::Decimal(b)) => Number::Decimal(a / b),
+        };
+
+        Ok(result.normalize())
+    }
+}
+
+impl std::ops::Rem for Number {
+    type Output = Result<Number, ShellError>;
+
+    fn rem(self, other: Number) -> Result<Number, ShellError> {
+        if other.is_zero() {
+            return Err(ShellError::untagged_runtime_error("Division by zero"));
+        }
+
+        let result = match (self, other) {
+            // `a % b` panics on plain `i64` for `i64::MIN % -1`; promote to
+            // `BigInt` on overflow the same way `Add`/`Sub`/`Mul` do.
+            (Number::Int(a), Number::Int(b)) => match a.checked_rem(b) {
+                Some(val) => Number::Int(val),
+                None => Number::BigInt(BigInt::from(a) % BigInt::from(b)),
+            },
+            (Number::BigInt(a), Number::Int(b)) => Number::BigInt(a % BigInt::from(b)),
+            (Number::Int(a), Number::BigInt(b)) => Number::BigInt(BigInt::from(a) % b),
+            (Number::BigInt(a), Number::BigInt(b)) => Number::BigInt(a % b),
+            (Number::Int(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) % b),
+            (Number::Decimal(a), Number::Int(b)) => Number::Decimal(a % BigDecimal::from(b)),
+            (Number::BigInt(a), Number::Decimal(b)) => Number::Decimal(BigDecimal::from(a) % b),
+            (Number::Decimal(a), Number::BigInt(b)) => Number::Decimal(a % BigDecimal::from(b)),
+            (Number::Decimal(a), Number::Decimal(b)) => Number::Decimal(a % b),
+        };
+
+        Ok(result.normalize())
+    }
+}
+
+#[cfg(test)]
+mod number_div_rem_tests {
+    use super::*;
+
+    #[test]
+    fn div_promotes_to_bigint_on_i64_min_over_neg_one() {
+        // `i64::MIN / -1` overflows `i64` and used to panic; it must now
+        // promote to `BigInt` instead.
+        let result = (Number::Int(i64::MIN) / Number::Int(-1)).expect("divisor isn't zero");
+        assert_eq!(
+            result,
+            Number::BigInt(BigInt::from(i64::MIN) / BigInt::from(-1))
+        );
+    }
+
+    #[test]
+    fn rem_of_i64_min_by_neg_one_is_zero_not_a_panic() {
+        // `i64::MIN % -1` overflows `i64` the same way the division does,
+        // even though the mathematical remainder is always zero.
+        let result = (Number::Int(i64::MIN) % Number::Int(-1)).expect("divisor isn't zero");
+        assert_eq!(result, Number::Int(0));
+    }
+
+    #[test]
+    fn div_stays_int_when_it_divides_evenly() {
+        let result = (Number::Int(10) / Number::Int(2)).expect("divisor isn't zero");
+        assert_eq!(result, Number::Int(5));
+    }
+
+    #[test]
+    fn rem_stays_int_for_ordinary_values() {
+        let result = (Number::Int(10) % Number::Int(3)).expect("divisor isn't zero");
+        assert_eq!(result, Number::Int(1));
+    }
+}
+
+impl std::ops::Neg for Number {
+    type Output = Number;
+
+    fn neg(self) -> Number {
         match self {
-            Number::BigInt(left) => Number::BigInt(left * (other as i64)),
-            Number::Int(left) => Number::Int(left * (other as i64)),
-            Number::Decimal(left) => Number::Decimal(left * BigDecimal::from(other)),
+            Number::Int(a) => match a.checked_neg() {
+                Some(val) => Number::Int(val),
+                None => Number::BigInt(-BigInt::from(a)),
+            },
+            Number::BigInt(a) => Number::BigInt(-a),
+            Number::Decimal(a) => Number::Decimal(-a),
         }
+        .normalize()
     }
 }
 
@@ -633,17 +996,30 @@ impl Unit {
 }
 
 pub fn filesize(size_in_bytes: Number) -> UntaggedValue {
-    match size_in_bytes {
-        Number::Int(i) => UntaggedValue::Primitive(Primitive::Filesize(i as u64)),
+    // `Primitive::Filesize` holds a `u64`, so this is where the `Number`
+    // (already multiplied out by `Unit::compute`) is narrowed. A decimal is
+    // rounded to whole bytes; a value that is negative or larger than
+    // `u64::MAX` becomes an error rather than being truncated. Sizes above
+    // `u64::MAX` bytes are not representable.
+    match size_in_bytes.normalize() {
+        Number::Int(i) => match u64::try_from(i) {
+            Ok(i) => UntaggedValue::Primitive(Primitive::Filesize(i)),
+            Err(_) => UntaggedValue::Error(ShellError::untagged_runtime_error(
+                "Negative number can't convert to filesize",
+            )),
+        },
         Number::BigInt(bi) => match bi.to_u64() {
             Some(i) => UntaggedValue::Primitive(Primitive::Filesize(i)),
             None => UntaggedValue::Error(ShellError::untagged_runtime_error(
                 "Big int too large to convert to filesize",
             )),
         },
-        Number::Decimal(_) => UntaggedValue::Error(ShellError::untagged_runtime_error(
-            "Decimal can't convert to filesize",
-        )),
+        Number::Decimal(decimal) => match decimal.round(0).to_u64() {
+            Some(i) => UntaggedValue::Primitive(Primitive::Filesize(i)),
+            None => UntaggedValue::Error(ShellError::untagged_runtime_error(
+                "Decimal is negative or too large to convert to filesize",
+            )),
+        },
     }
 }
 
@@ -663,7 +1039,16 @@ impl SpannedExpression {
     }
 
     pub fn precedence(&self) -> usize {
-        match self.expr {
+        self.precedence_with(None)
+    }
+
+    /// Like [`precedence`](Self::precedence), but consults `registry` for
+    /// `Operator::Custom` tokens so user-defined infix operators registered
+    /// there take part in the same precedence-climbing parse as the built-ins. A
+    /// custom operator that isn't registered binds loosest (precedence 0),
+    /// same as any other non-operator token.
+    pub fn precedence_with(&self, registry: Option<&OperatorRegistry>) -> usize {
+        match &self.expr {
             Expression::Literal(Literal::Operator(operator)) => {
                 // Higher precedence binds tighter
 
@@ -683,6 +1068,10 @@ impl SpannedExpression {
                     | Operator::NotIn => 80,
                     Operator::And => 50,
                     Operator::Or => 40, // TODO: should we have And and Or be different precedence?
+                    Operator::Custom(token) => registry
+                        .and_then(|registry| registry.get(token))
+                        .map(|def| def.precedence as usize)
+                        .unwrap_or(0),
                 }
             }
             _ => 0,
@@ -726,6 +1115,429 @@ impl SpannedExpression {
             Ok(var_name)
         }
     }
+
+    /// Compile-time evaluate subtrees whose operands are all literals,
+    /// recursing bottom-up so nested constant arithmetic like `(2 + 3) * 4`
+    /// collapses to a single `Literal::Number`. Any node that reaches a
+    /// `Variable`, `Subexpression`, `ExternalCommand`, or `Garbage` is left
+    /// untouched, as is a fold that would divide/mod by zero or blow up a
+    /// `Pow` exponent — normalization never changes what a script means.
+    pub fn normalize(&self) -> SpannedExpression {
+        struct Normalize;
+
+        impl ExpressionFolder for Normalize {
+            fn fold_binary(&mut self, binary: Binary) -> Expression {
+                match fold_binary_literals(&binary.left, &binary.op, &binary.right) {
+                    Some(folded) => folded,
+                    None => Expression::Binary(Box::new(binary)),
+                }
+            }
+        }
+
+        fold_expression(&mut Normalize, self)
+    }
+
+    /// Render this expression back into valid, round-trippable Nu source.
+    /// Unlike [`PrettyDebugWithSource`], which decorates nodes with debug
+    /// markers (`typed`, `b"..."`, `<left op right>`) meant for a human
+    /// reading a parse tree, `to_source` emits text a parser could read back
+    /// in — this is what lets folded literals (see [`normalize`](
+    /// Self::normalize)) print their computed value instead of whatever text
+    /// happened to be at their span. The one exception is a `Garbage` node,
+    /// which has no valid source form and prints as `<garbage>`.
+    pub fn to_source(&self, source: &str) -> String {
+        to_source_at(self, source, 0)
+    }
+
+    /// Replace every free occurrence of `$name` with `replacement`.
+    /// Equivalent to [`substitute_all`](Self::substitute_all) with a single
+    /// binding; see there for what "free" and "spliced-in span" mean.
+    pub fn substitute(&self, name: &str, replacement: &SpannedExpression) -> SpannedExpression {
+        let mut bindings = HashMap::new();
+        bindings.insert(name.to_string(), replacement.clone());
+        self.substitute_all(&bindings)
+    }
+
+    /// Replace every free occurrence of each `$name` in `bindings` with its
+    /// paired expression, recursing through the same tree shape
+    /// [`get_free_variables`](Self::get_free_variables) walks. A variable is
+    /// free unless an enclosing `Block`/`Subexpression`'s own parameter list
+    /// rebinds it — descending into one stops substituting any name it
+    /// shadows, the same capture boundary `get_free_variables` already
+    /// respects. Every spliced-in occurrence is retagged with the
+    /// occurrence's span rather than `replacement`'s original one, so
+    /// downstream error messages and the highlighter point at the use site,
+    /// not the alias/macro definition.
+    pub fn substitute_all(
+        &self,
+        bindings: &HashMap<String, SpannedExpression>,
+    ) -> SpannedExpression {
+        if bindings.is_empty() {
+            return self.clone();
+        }
+
+        fold_expression(&mut SubstituteFolder { bindings }, self)
+    }
+}
+
+/// `min_prec` is the precedence of the operator the caller is about to place
+/// this expression under; an operand binds looser than that has to be
+/// parenthesized or the printed expression would parse differently than the
+/// tree it came from.
+fn to_source_at(expr: &SpannedExpression, source: &str, min_prec: usize) -> String {
+    match &expr.expr {
+        Expression::Literal(literal) => literal_to_source(literal, expr.span, source),
+        Expression::ExternalWord => expr.span.slice(source).to_string(),
+        Expression::Synthetic(Synthetic::String(string)) => quote_string(string),
+        Expression::Variable(..) => expr.span.slice(source).to_string(),
+        Expression::Binary(binary) => {
+            // `expr` is the `Binary` node itself, which never matches
+            // `Literal::Operator` so `expr.precedence()` is always 0 — the
+            // precedence we need is the operator token's, `binary.op`.
+            let prec = binary.op.precedence();
+            // A right-associative operator (`Pow`) chains to the right, so
+            // there it's the left operand at equal precedence that needs
+            // parentheses instead of the right one.
+            let (left_prec, right_prec) = match &binary.op.expr {
+                Expression::Literal(Literal::Operator(operator))
+                    if operator.associativity_with(None) == Associativity::Right =>
+                {
+                    (prec + 1, prec)
+                }
+                _ => (prec, prec + 1),
+            };
+            let rendered = format!(
+                "{} {} {}",
+                to_source_at(&binary.left, source, left_prec),
+                binary.op.span.slice(source),
+                to_source_at(&binary.right, source, right_prec),
+            );
+
+            if prec < min_prec {
+                format!("({})", rendered)
+            } else {
+                rendered
+            }
+        }
+        Expression::Range(range) => {
+            let left = range
+                .left
+                .as_ref()
+                .map(|expr| to_source_at(expr, source, 0))
+                .unwrap_or_default();
+            let right = range
+                .right
+                .as_ref()
+                .map(|expr| to_source_at(expr, source, 0))
+                .unwrap_or_default();
+
+            format!("{}{}{}", left, range.operator.span().slice(source), right)
+        }
+        Expression::Block(block) | Expression::Subexpression(block) => {
+            block.span.slice(source).to_string()
+        }
+        Expression::List(list) => format!(
+            "[{}]",
+            list.iter()
+                .map(|item| to_source_at(item, source, 0))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        Expression::Table(headers, rows) => {
+            let headers = headers
+                .iter()
+                .map(|header| to_source_at(header, source, 0))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    format!(
+                        "[{}]",
+                        row.iter()
+                            .map(|cell| to_source_at(cell, source, 0))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            format!("[[{}]; {}]", headers, rows)
+        }
+        Expression::FullColumnPath(path) => {
+            let mut rendered = to_source_at(&path.head, source, 0);
+            for member in &path.tail {
+                rendered.push('.');
+                rendered.push_str(&path_member_to_source(member));
+            }
+            rendered
+        }
+        Expression::FilePath(path) => path.display().to_string(),
+        Expression::ExternalCommand(external) => {
+            let mut rendered = format!("^{}", external.name.item);
+            for arg in &external.args {
+                rendered.push(' ');
+                rendered.push_str(&arg.item);
+            }
+            rendered
+        }
+        Expression::Command => expr.span.slice(source).to_string(),
+        Expression::Boolean(boolean) => match boolean {
+            true => "$yes".to_string(),
+            false => "$no".to_string(),
+        },
+        Expression::Garbage => "<garbage>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod to_source_round_trip_tests {
+    use super::*;
+
+    fn int(i: i64) -> SpannedExpression {
+        Expression::integer(i).into_spanned(Span::new(0, 0))
+    }
+
+    fn binary(left: SpannedExpression, op: Operator, op_span: Span, right: SpannedExpression) -> SpannedExpression {
+        let span = left.span.until(right.span);
+        Expression::Binary(Box::new(Binary::new(
+            left,
+            Expression::operator(op).into_spanned(op_span),
+            right,
+        )))
+        .into_spanned(span)
+    }
+
+    #[test]
+    fn left_nested_binary_parenthesizes_the_looser_left_child() {
+        // (2 + 3) * 4 — without parens around the `Plus`, printing this as
+        // `2 + 3 * 4` would re-parse as `2 + (3 * 4) = 14` instead of `20`.
+        let source = "+*";
+        let plus = binary(int(2), Operator::Plus, Span::new(0, 1), int(3));
+        let tree = binary(plus, Operator::Multiply, Span::new(1, 2), int(4));
+
+        assert_eq!(tree.to_source(source), "(2 + 3) * 4");
+    }
+
+    #[test]
+    fn right_nested_binary_parenthesizes_the_right_child() {
+        // 2 - (3 - 4) — `Minus` is left-associative, so the right child has
+        // to be parenthesized or `2 - 3 - 4` would re-parse as `(2 - 3) - 4`.
+        let source = "--";
+        let inner = binary(int(3), Operator::Minus, Span::new(1, 2), int(4));
+        let tree = binary(int(2), Operator::Minus, Span::new(0, 1), inner);
+
+        assert_eq!(tree.to_source(source), "2 - (3 - 4)");
+    }
+}
+
+fn literal_to_source(literal: &Literal, span: Span, source: &str) -> String {
+    match literal {
+        Literal::Number(number) => number_to_source(number),
+        Literal::Size(number, unit) => format!(
+            "{}{}",
+            number_to_source(&number.item),
+            unit.item.as_str().to_ascii_lowercase()
+        ),
+        Literal::Operator(_) => span.slice(source).to_string(),
+        Literal::String(string) => quote_string(string),
+        Literal::GlobPattern(pattern) => pattern.clone(),
+        Literal::ColumnPath(members) => members
+            .iter()
+            .map(|member| member_to_source(member, source))
+            .collect::<Vec<_>>()
+            .join("."),
+        Literal::Bare(bare) => bare.clone(),
+    }
+}
+
+fn number_to_source(number: &Number) -> String {
+    match number {
+        Number::BigInt(bi) => bi.to_string(),
+        Number::Int(i) => i.to_string(),
+        Number::Decimal(decimal) => decimal.to_string(),
+    }
+}
+
+fn member_to_source(member: &Member, source: &str) -> String {
+    match member {
+        Member::String(outer, _) => outer.slice(source).to_string(),
+        Member::Int(int, _) => int.to_string(),
+        Member::Bare(bare) => bare.item.clone(),
+    }
+}
+
+fn path_member_to_source(member: &PathMember) -> String {
+    match &member.unspanned {
+        UnspannedPathMember::String(string) => bare_or_quoted(string),
+        UnspannedPathMember::Int(int) => int.to_string(),
+    }
+}
+
+/// A bare column path member (`foo.bar`) doesn't need quotes; anything with
+/// whitespace or punctuation in it has to be re-quoted to parse back the same
+/// way (`foo."two words"`).
+fn bare_or_quoted(string: &str) -> String {
+    let is_bare = !string.is_empty()
+        && string
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+
+    if is_bare {
+        string.to_string()
+    } else {
+        quote_string(string)
+    }
+}
+
+fn quote_string(string: &str) -> String {
+    let mut rendered = String::with_capacity(string.len() + 2);
+    rendered.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => rendered.push_str("\\\""),
+            '\\' => rendered.push_str("\\\\"),
+            '\n' => rendered.push_str("\\n"),
+            '\t' => rendered.push_str("\\t"),
+            _ => rendered.push(c),
+        }
+    }
+    rendered.push('"');
+    rendered
+}
+
+/// Try to compile-time evaluate `left op right`. Returns `None` (leave the
+/// node as a `Binary`) unless both operands are literals of a kind the
+/// operator is defined over.
+fn fold_binary_literals(
+    left: &SpannedExpression,
+    op: &SpannedExpression,
+    right: &SpannedExpression,
+) -> Option<Expression> {
+    let operator = match &op.expr {
+        Expression::Literal(Literal::Operator(operator)) => operator,
+        _ => return None,
+    };
+
+    match (&left.expr, &right.expr) {
+        (Expression::Literal(Literal::Number(a)), Expression::Literal(Literal::Number(b))) => {
+            let (a, b) = (a.clone(), b.clone());
+            let folded = match operator {
+                Operator::Plus => Some(a.clone() + b.clone()),
+                Operator::Minus => Some(a.clone() - b.clone()),
+                Operator::Multiply => Some(a.clone() * b.clone()),
+                Operator::Divide => (a.clone() / b.clone()).ok(),
+                Operator::Modulo => (a.clone() % b.clone()).ok(),
+                Operator::Pow => fold_pow(a.clone(), b.clone()),
+                _ => None,
+            };
+
+            match folded {
+                Some(number) => Some(Expression::Literal(Literal::Number(number))),
+                None => fold_comparison(operator, &left.expr, &right.expr),
+            }
+        }
+        (Expression::Literal(Literal::String(a)), Expression::Literal(Literal::String(b))) => {
+            match operator {
+                Operator::Plus => Some(Expression::string(format!("{}{}", a, b))),
+                _ => fold_comparison(operator, &left.expr, &right.expr),
+            }
+        }
+        (Expression::Boolean(a), Expression::Boolean(b)) => match operator {
+            Operator::And => Some(Expression::boolean(*a && *b)),
+            Operator::Or => Some(Expression::boolean(*a || *b)),
+            _ => fold_comparison(operator, &left.expr, &right.expr),
+        },
+        _ => None,
+    }
+}
+
+fn fold_comparison(operator: &Operator, left: &Expression, right: &Expression) -> Option<Expression> {
+    let ordering = match (left, right) {
+        (Expression::Literal(Literal::Number(a)), Expression::Literal(Literal::Number(b))) => {
+            a.cmp(b)
+        }
+        (Expression::Literal(Literal::String(a)), Expression::Literal(Literal::String(b))) => {
+            a.cmp(b)
+        }
+        (Expression::Boolean(a), Expression::Boolean(b)) => a.cmp(b),
+        _ => return None,
+    };
+
+    let result = match operator {
+        Operator::Equal => ordering == Ordering::Equal,
+        Operator::NotEqual => ordering != Ordering::Equal,
+        Operator::LessThan => ordering == Ordering::Less,
+        Operator::LessThanOrEqual => ordering != Ordering::Greater,
+        Operator::GreaterThan => ordering == Ordering::Greater,
+        Operator::GreaterThanOrEqual => ordering != Ordering::Less,
+        _ => return None,
+    };
+
+    Some(Expression::boolean(result))
+}
+
+/// Only folds small, non-negative integer exponents: a negative exponent
+/// isn't meaningful for `Number`'s integer/decimal split, and an unbounded
+/// one risks doing an expensive computation at parse time for no benefit.
+///
+/// Bounding the exponent alone isn't enough, though: folding recurses
+/// bottom-up, so nested `Pow`s compound (`((2**1000)**1000)**1000` folds the
+/// innermost `Pow` first, then feeds its already-huge result in as the next
+/// `base`) before any execution-time limit ever applies. So the running
+/// result's own magnitude is checked after every multiplication, and folding
+/// bails out — leaving the expression unfolded rather than finishing the
+/// computation — once it would need more than `MAX_FOLDED_POW_BITS` bits to
+/// represent.
+const MAX_FOLDED_POW_BITS: u64 = 4096;
+
+fn fold_pow(base: Number, exponent: Number) -> Option<Number> {
+    let exponent = match exponent {
+        Number::Int(exponent) if (0..=1000).contains(&exponent) => exponent as u32,
+        _ => return None,
+    };
+
+    let mut result = Number::Int(1);
+    for _ in 0..exponent {
+        result = result * base.clone();
+        if number_bits(&result) > MAX_FOLDED_POW_BITS {
+            return None;
+        }
+    }
+    Some(result)
+}
+
+/// Approximate magnitude of `number`, in bits, used to bound how large a
+/// constant-folded `Pow` is allowed to grow (see [`fold_pow`]).
+fn number_bits(number: &Number) -> u64 {
+    match number {
+        Number::Int(_) => i64::BITS as u64,
+        Number::BigInt(int) => int.bits(),
+        Number::Decimal(decimal) => {
+            let (digits, _) = decimal.as_bigint_and_exponent();
+            digits.bits()
+        }
+    }
+}
+
+#[cfg(test)]
+mod fold_pow_tests {
+    use super::*;
+
+    #[test]
+    fn small_powers_still_fold() {
+        assert_eq!(fold_pow(Number::Int(2), Number::Int(10)), Some(Number::Int(1024)));
+    }
+
+    #[test]
+    fn a_huge_base_bails_out_instead_of_computing_it() {
+        // `2**1000` is 1001 bits, still within `MAX_FOLDED_POW_BITS`; feeding
+        // it back in as the next `base` is exactly the compounding this
+        // bound exists to stop, and it bails out after a few multiplications.
+        let huge = fold_pow(Number::Int(2), Number::Int(1000)).expect("within the exponent bound");
+        assert_eq!(fold_pow(huge, Number::Int(1000)), None);
+    }
 }
 
 impl std::ops::Deref for SpannedExpression {
@@ -868,7 +1680,7 @@ impl PrettyDebugWithSource for SpannedExpression {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialOrd, Ord, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialOrd, Ord, Eq, Hash, PartialEq, Deserialize, Serialize)]
 pub enum Operator {
     Equal,
     NotEqual,
@@ -888,6 +1700,87 @@ pub enum Operator {
     And,
     Or,
     Pow,
+    /// A user-defined infix operator (e.g. `|>`), looked up by its token
+    /// text in an `OperatorRegistry`. The `String` is the operator's token
+    /// text.
+    Custom(String),
+}
+
+/// Binding direction for an infix operator: whether a chain of equal
+/// precedence folds from the left (`1 - 2 - 3` == `(1 - 2) - 3`) or the
+/// right (`2 ^ 3 ^ 2` == `2 ^ (3 ^ 2)`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+/// How a user-defined infix operator binds and what it desugars to.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct OperatorDefinition {
+    pub precedence: u8,
+    pub associativity: Associativity,
+    /// The command invoked in place of the operator, e.g. `|>` desugaring
+    /// to a `Call` against `command`.
+    pub command: String,
+}
+
+impl OperatorDefinition {
+    pub fn new(
+        precedence: u8,
+        associativity: Associativity,
+        command: impl Into<String>,
+    ) -> OperatorDefinition {
+        OperatorDefinition {
+            precedence,
+            associativity,
+            command: command.into(),
+        }
+    }
+}
+
+/// Maps a custom operator's token text to its precedence, associativity, and
+/// the command it desugars to. Entries are added only through `register`;
+/// there is no script-level syntax here for declaring one. Consulted by
+/// `SpannedExpression::precedence_with` and `climb_precedence`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct OperatorRegistry {
+    operators: IndexMap<String, OperatorDefinition>,
+}
+
+impl OperatorRegistry {
+    pub fn new() -> OperatorRegistry {
+        Default::default()
+    }
+
+    pub fn register(&mut self, token: impl Into<String>, definition: OperatorDefinition) {
+        self.operators.insert(token.into(), definition);
+    }
+
+    pub fn get(&self, token: &str) -> Option<&OperatorDefinition> {
+        self.operators.get(token)
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.operators.contains_key(token)
+    }
+}
+
+impl Operator {
+    /// Binding direction used by precedence-climbing: every built-in is
+    /// left-associative except `Pow`, which chains right (`2 ^ 3 ^ 2` ==
+    /// `2 ^ (3 ^ 2)`). A custom operator looks up its associativity in
+    /// `registry`, defaulting to left when unregistered.
+    pub fn associativity_with(&self, registry: Option<&OperatorRegistry>) -> Associativity {
+        match self {
+            Operator::Pow => Associativity::Right,
+            Operator::Custom(token) => registry
+                .and_then(|registry| registry.get(token))
+                .map(|def| def.associativity)
+                .unwrap_or(Associativity::Left),
+            _ => Associativity::Left,
+        }
+    }
 }
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Hash, Deserialize, Serialize, new)]
@@ -897,6 +1790,26 @@ pub struct Binary {
     pub right: SpannedExpression,
 }
 
+impl Binary {
+    /// If `op` is a registered custom operator, desugar `left op right` into
+    /// an `InternalCommand` call against the operator's command (`left`/
+    /// `right` become its two positional arguments), so the rest of
+    /// evaluation doesn't need to know custom operators exist.
+    pub fn desugar_custom(&self, registry: &OperatorRegistry) -> Option<InternalCommand> {
+        let token = match &self.op.expr {
+            Expression::Literal(Literal::Operator(Operator::Custom(token))) => token,
+            _ => return None,
+        };
+        let definition = registry.get(token)?;
+        let full_span = self.left.span.until(self.right.span);
+
+        let mut internal =
+            InternalCommand::new(definition.command.clone(), self.op.span, full_span);
+        internal.args.positional = Some(vec![self.left.clone(), self.right.clone()]);
+        Some(internal)
+    }
+}
+
 impl PrettyDebugWithSource for Binary {
     fn pretty_debug(&self, source: &str) -> DebugDocBuilder {
         DbgDocBldr::delimit(
@@ -912,6 +1825,84 @@ impl PrettyDebugWithSource for Binary {
     }
 }
 
+/// Rebuild a correctly-nested `Expression::Binary` tree from a flat, already
+/// parsed sequence of operands and the operators between them (so `1 + 2 * 3`
+/// nests as `1 + (2 * 3)` instead of however the caller happened to collect
+/// the tokens). Uses the classic precedence-climbing algorithm: operators
+/// bind according to `SpannedExpression::precedence_with`, with right-
+/// associative operators (`Pow`) recursing at the same minimum precedence so
+/// they chain to the right instead of the left.
+///
+/// `operands.len()` must be `operators.len() + 1`; returns `None` if
+/// `operands` is empty.
+pub fn climb_precedence(
+    operands: &[SpannedExpression],
+    operators: &[SpannedExpression],
+    registry: Option<&OperatorRegistry>,
+) -> Option<SpannedExpression> {
+    if operands.is_empty() {
+        return None;
+    }
+
+    let mut operand_idx = 0;
+    let mut operator_idx = 0;
+    Some(climb(
+        operands,
+        operators,
+        &mut operand_idx,
+        &mut operator_idx,
+        0,
+        registry,
+    ))
+}
+
+fn climb(
+    operands: &[SpannedExpression],
+    operators: &[SpannedExpression],
+    operand_idx: &mut usize,
+    operator_idx: &mut usize,
+    min_prec: usize,
+    registry: Option<&OperatorRegistry>,
+) -> SpannedExpression {
+    let mut left = operands[*operand_idx].clone();
+    *operand_idx += 1;
+
+    while let Some(op) = operators.get(*operator_idx) {
+        let prec = op.precedence_with(registry);
+        if prec < min_prec {
+            break;
+        }
+        *operator_idx += 1;
+
+        let associativity = match &op.expr {
+            Expression::Literal(Literal::Operator(operator)) => {
+                operator.associativity_with(registry)
+            }
+            _ => Associativity::Left,
+        };
+        let next_min_prec = match associativity {
+            Associativity::Left => prec + 1,
+            Associativity::Right => prec,
+        };
+
+        let right = climb(
+            operands,
+            operators,
+            operand_idx,
+            operator_idx,
+            next_min_prec,
+            registry,
+        );
+        let span = left.span.until(right.span);
+        left = SpannedExpression::new(
+            Expression::Binary(Box::new(Binary::new(left, op.clone(), right))),
+            span,
+        );
+    }
+
+    left
+}
+
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Hash, Deserialize, Serialize)]
 pub enum Synthetic {
     String(String),
@@ -1189,79 +2180,372 @@ impl Expression {
     }
 
     pub fn has_var_usage(&self, var_name: &str) -> bool {
-        match self {
-            Expression::Variable(name, _) if name == var_name => true,
-            Expression::Table(headers, values) => {
-                headers.iter().any(|se| se.has_var_usage(var_name))
-                    || values
-                        .iter()
-                        .any(|v| v.iter().any(|se| se.has_var_usage(var_name)))
-            }
-            Expression::List(list) => list.iter().any(|se| se.has_var_usage(var_name)),
-            Expression::Subexpression(block) => block.has_var_usage(var_name),
-            Expression::Binary(binary) => {
-                binary.left.has_var_usage(var_name) || binary.right.has_var_usage(var_name)
+        struct VarUsage<'a> {
+            var_name: &'a str,
+            found: bool,
+        }
+
+        impl<'a> ExpressionVisitor for VarUsage<'a> {
+            fn visit_variable(&mut self, name: &str, _span: Span) {
+                self.found |= name == self.var_name;
             }
-            Expression::FullColumnPath(path) => path.head.has_var_usage(var_name),
-            Expression::Range(range) => {
-                (if let Some(left) = &range.left {
-                    left.has_var_usage(var_name)
-                } else {
-                    false
-                }) || (if let Some(right) = &range.right {
-                    right.has_var_usage(var_name)
-                } else {
-                    false
-                })
+
+            fn visit_block(&mut self, block: &Arc<Block>) {
+                self.found |= block.has_var_usage(self.var_name);
             }
-            _ => false,
         }
+
+        let mut visitor = VarUsage {
+            var_name,
+            found: false,
+        };
+        walk_expression(&mut visitor, self);
+        visitor.found
     }
 
     pub fn get_free_variables(&self, known_variables: &mut Vec<String>) -> Vec<String> {
-        let mut output = vec![];
-        match self {
-            Expression::Variable(name, _) => {
-                if !known_variables.contains(name) {
-                    output.push(name.clone());
+        struct FreeVariables<'a> {
+            known_variables: &'a mut Vec<String>,
+            found: Vec<String>,
+        }
+
+        impl<'a> ExpressionVisitor for FreeVariables<'a> {
+            fn visit_variable(&mut self, name: &str, _span: Span) {
+                if !self.known_variables.contains(&name.to_string()) {
+                    self.found.push(name.to_string());
                 }
             }
-            Expression::Table(headers, values) => {
-                for header in headers {
-                    output.extend(header.get_free_variables(known_variables));
-                }
-                for row in values {
-                    for value in row {
-                        output.extend(value.get_free_variables(known_variables));
-                    }
-                }
+
+            fn visit_block(&mut self, block: &Arc<Block>) {
+                self.found
+                    .extend(block.get_free_variables(self.known_variables));
             }
-            Expression::List(list) => {
-                for item in list {
-                    output.extend(item.get_free_variables(known_variables));
-                }
+        }
+
+        let mut visitor = FreeVariables {
+            known_variables,
+            found: vec![],
+        };
+        walk_expression(&mut visitor, self);
+        visitor.found
+    }
+}
+
+/// A structural walk over `Expression` trees. Implement the `visit_*` hooks
+/// you care about; `walk_expression` supplies the default recursion through
+/// `Table`/`List`/`Binary`/`Range`/`FullColumnPath`/`Subexpression` so each
+/// new analysis (constant folding, substitution, span collection, ...)
+/// doesn't have to repeat the same match arms.
+pub trait ExpressionVisitor {
+    fn visit_variable(&mut self, _name: &str, _span: Span) {}
+
+    /// Called for `Expression::Block`/`Expression::Subexpression`. The
+    /// default does nothing; most analyses delegate to the block's own
+    /// `has_var_usage`/`get_free_variables` here rather than walking into it.
+    fn visit_block(&mut self, _block: &Arc<Block>) {}
+}
+
+pub fn walk_expression<V: ExpressionVisitor + ?Sized>(visitor: &mut V, expr: &Expression) {
+    match expr {
+        Expression::Variable(name, span) => visitor.visit_variable(name, *span),
+        Expression::Table(headers, values) => {
+            for header in headers {
+                walk_expression(visitor, &header.expr);
             }
-            Expression::Subexpression(block) | Expression::Block(block) => {
-                output.extend(block.get_free_variables(known_variables));
+            for row in values {
+                for value in row {
+                    walk_expression(visitor, &value.expr);
+                }
             }
-            Expression::Binary(binary) => {
-                output.extend(binary.left.get_free_variables(known_variables));
-                output.extend(binary.right.get_free_variables(known_variables));
+        }
+        Expression::List(list) => {
+            for item in list {
+                walk_expression(visitor, &item.expr);
             }
-            Expression::FullColumnPath(path) => {
-                output.extend(path.head.get_free_variables(known_variables));
+        }
+        Expression::Subexpression(block) | Expression::Block(block) => {
+            visitor.visit_block(block);
+        }
+        Expression::Binary(binary) => {
+            walk_expression(visitor, &binary.left.expr);
+            walk_expression(visitor, &binary.right.expr);
+        }
+        Expression::FullColumnPath(path) => walk_expression(visitor, &path.head.expr),
+        Expression::Range(range) => {
+            if let Some(left) = &range.left {
+                walk_expression(visitor, &left.expr);
             }
-            Expression::Range(range) => {
-                if let Some(left) = &range.left {
-                    output.extend(left.get_free_variables(known_variables));
-                }
-                if let Some(right) = &range.right {
-                    output.extend(right.get_free_variables(known_variables));
-                }
+            if let Some(right) = &range.right {
+                walk_expression(visitor, &right.expr);
             }
-            _ => {}
         }
-        output
+        _ => {}
+    }
+}
+
+/// The mutating counterpart to [`ExpressionVisitor`]: rebuilds an
+/// `Expression` tree bottom-up instead of only visiting it, so transforms
+/// like constant folding ([`SpannedExpression::normalize`]) and variable
+/// substitution ([`SpannedExpression::substitute_all`]) share the same
+/// recursion through `Table`/`List`/`Binary`/`Range`/`FullColumnPath`
+/// instead of each hand-rolling it. Override `fold_variable`/`fold_block`/
+/// `fold_binary` for the nodes you want to rewrite; `fold_expression`
+/// supplies the default recursion, and its defaults leave every node
+/// exactly as it was (an identity fold).
+pub trait ExpressionFolder {
+    fn fold_variable(&mut self, name: &str, span: Span) -> Expression {
+        Expression::Variable(name.to_string(), span)
+    }
+
+    /// Rewrite the `Block` payload of `Expression::Block`/
+    /// `Expression::Subexpression`. The default leaves it alone, same as
+    /// `ExpressionVisitor::visit_block`'s default of not descending.
+    fn fold_block(&mut self, block: &Arc<Block>) -> Arc<Block> {
+        block.clone()
+    }
+
+    /// Called with a `Binary` whose `left`/`right` have already been
+    /// folded, to rebuild (or rewrite — this is where constant folding
+    /// hooks in) the node itself.
+    fn fold_binary(&mut self, binary: Binary) -> Expression {
+        Expression::Binary(Box::new(binary))
+    }
+}
+
+pub fn fold_expression<F: ExpressionFolder + ?Sized>(
+    folder: &mut F,
+    expr: &SpannedExpression,
+) -> SpannedExpression {
+    let folded = match &expr.expr {
+        Expression::Variable(name, span) => folder.fold_variable(name, *span),
+        Expression::Table(headers, rows) => Expression::Table(
+            headers
+                .iter()
+                .map(|header| fold_expression(folder, header))
+                .collect(),
+            rows.iter()
+                .map(|row| row.iter().map(|cell| fold_expression(folder, cell)).collect())
+                .collect(),
+        ),
+        Expression::List(list) => Expression::List(
+            list.iter()
+                .map(|item| fold_expression(folder, item))
+                .collect(),
+        ),
+        Expression::Subexpression(block) => Expression::Subexpression(folder.fold_block(block)),
+        Expression::Block(block) => Expression::Block(folder.fold_block(block)),
+        Expression::Binary(binary) => {
+            let left = fold_expression(folder, &binary.left);
+            let right = fold_expression(folder, &binary.right);
+            folder.fold_binary(Binary::new(left, binary.op.clone(), right))
+        }
+        Expression::FullColumnPath(path) => Expression::FullColumnPath(Box::new(
+            FullColumnPath::new(fold_expression(folder, &path.head), path.tail.clone()),
+        )),
+        Expression::Range(range) => Expression::Range(Box::new(Range {
+            left: range.left.as_ref().map(|expr| fold_expression(folder, expr)),
+            operator: range.operator.clone(),
+            right: range.right.as_ref().map(|expr| fold_expression(folder, expr)),
+        })),
+        other => other.clone(),
+    };
+
+    folded.into_spanned(expr.span)
+}
+
+/// A structural walk over the call-argument layer (`NamedArguments`/`Call`),
+/// layered on top of `ExpressionVisitor` for the expressions it eventually
+/// bottoms out at — in the spirit of `rustc_ast::visit`. Each hook's default
+/// body, except the leaf `visit_flag`'s empty one, calls the matching `walk_*`
+/// free function, so overriding one hook
+/// still recurses into the rest of the tree; override `visit_expression` (or
+/// an `ExpressionVisitor` hook) if you need to stop descending early.
+pub trait Visitor: ExpressionVisitor {
+    fn visit_expression(&mut self, expr: &Expression) {
+        walk_expression(self, expr);
+    }
+
+    /// Called for a flag that's actually present — `NamedValue::PresentSwitch`
+    /// — with the flag's own name and the span of its occurrence. Absent
+    /// switches and `Value` flags (which carry an expression; see
+    /// `visit_named_value`) don't reach this hook.
+    fn visit_flag(&mut self, _name: &str, _span: Span) {}
+
+    fn visit_named_value(&mut self, name: &str, value: &NamedValue) {
+        walk_named_value(self, name, value);
+    }
+
+    fn visit_named_arguments(&mut self, named: &NamedArguments) {
+        walk_named_arguments(self, named);
+    }
+
+    fn visit_call(&mut self, call: &Call) {
+        walk_call(self, call);
+    }
+}
+
+pub fn walk_named_value<V: Visitor + ?Sized>(visitor: &mut V, name: &str, value: &NamedValue) {
+    match value {
+        NamedValue::PresentSwitch(span) => visitor.visit_flag(name, *span),
+        NamedValue::Value(_, expr) => visitor.visit_expression(&expr.expr),
+        NamedValue::AbsentSwitch | NamedValue::AbsentValue => {}
+    }
+}
+
+pub fn walk_named_arguments<V: Visitor + ?Sized>(visitor: &mut V, named: &NamedArguments) {
+    for (name, value) in named.iter() {
+        visitor.visit_named_value(name, value);
+    }
+}
+
+pub fn walk_call<V: Visitor + ?Sized>(visitor: &mut V, call: &Call) {
+    visitor.visit_expression(&call.head.expr);
+
+    if let Some(positional) = &call.positional {
+        for expr in positional {
+            visitor.visit_expression(&expr.expr);
+        }
+    }
+
+    if let Some(named) = &call.named {
+        visitor.visit_named_arguments(named);
+    }
+}
+
+/// The mutating counterpart to [`Visitor`]: rebuilds the call-argument layer
+/// (`NamedArguments`/`Call`) instead of only visiting it, built on
+/// [`ExpressionFolder`] the same way `Visitor` is built on
+/// `ExpressionVisitor`. Each hook's default body calls the matching
+/// `walk_*_mut` free function and its default behavior is an identity fold.
+pub trait VisitorMut: ExpressionFolder {
+    fn fold_expression(&mut self, expr: &SpannedExpression) -> SpannedExpression {
+        fold_expression(self, expr)
+    }
+
+    fn fold_named_value(&mut self, value: &NamedValue) -> NamedValue {
+        walk_named_value_mut(self, value)
+    }
+
+    fn fold_named_arguments(&mut self, named: &NamedArguments) -> NamedArguments {
+        walk_named_arguments_mut(self, named)
+    }
+
+    fn fold_call(&mut self, call: &Call) -> Call {
+        walk_call_mut(self, call)
+    }
+}
+
+pub fn walk_named_value_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    value: &NamedValue,
+) -> NamedValue {
+    match value {
+        NamedValue::Value(span, expr) => {
+            NamedValue::Value(*span, Box::new(visitor.fold_expression(expr)))
+        }
+        other => other.clone(),
+    }
+}
+
+pub fn walk_named_arguments_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    named: &NamedArguments,
+) -> NamedArguments {
+    NamedArguments {
+        named: named
+            .named
+            .iter()
+            .map(|(name, value)| (name.clone(), visitor.fold_named_value(value)))
+            .collect(),
+        groups: named.groups.clone(),
+        conflicts: named.conflicts.clone(),
+    }
+}
+
+pub fn walk_call_mut<V: VisitorMut + ?Sized>(visitor: &mut V, call: &Call) -> Call {
+    Call {
+        head: Box::new(visitor.fold_expression(&call.head)),
+        positional: call.positional.as_ref().map(|positional| {
+            positional
+                .iter()
+                .map(|expr| visitor.fold_expression(expr))
+                .collect()
+        }),
+        named: call.named.as_ref().map(|named| visitor.fold_named_arguments(named)),
+        span: call.span,
+        external_redirection: call.external_redirection,
+    }
+}
+
+/// Shared [`ExpressionFolder`]/[`VisitorMut`] behind
+/// [`SpannedExpression::substitute_all`], [`Call::substitute_all`] and
+/// [`NamedArguments::substitute_all`]: replace a free variable occurrence
+/// with its binding (retagged to the occurrence's span by `fold_expression`),
+/// and recurse into a block through its own capture-avoiding
+/// `Block::substitute_all`.
+struct SubstituteFolder<'a> {
+    bindings: &'a HashMap<String, SpannedExpression>,
+}
+
+impl<'a> ExpressionFolder for SubstituteFolder<'a> {
+    fn fold_variable(&mut self, name: &str, span: Span) -> Expression {
+        match self.bindings.get(name) {
+            Some(replacement) => replacement.expr.clone(),
+            None => Expression::Variable(name.to_string(), span),
+        }
+    }
+
+    fn fold_block(&mut self, block: &Arc<Block>) -> Arc<Block> {
+        Arc::new(block.substitute_all(self.bindings))
+    }
+}
+
+impl<'a> VisitorMut for SubstituteFolder<'a> {}
+
+/// Reference [`Visitor`] that collects every free variable reached during a
+/// walk — the same notion [`Expression::get_free_variables`] uses: a
+/// variable is free unless it's already in `known_variables`, and descending
+/// into a block delegates to the block's own accounting, so a parameter the
+/// block declares doesn't count as free inside it.
+pub struct FreeVariableCollector<'a> {
+    pub known_variables: &'a mut Vec<String>,
+    pub found: Vec<String>,
+}
+
+impl<'a> ExpressionVisitor for FreeVariableCollector<'a> {
+    fn visit_variable(&mut self, name: &str, _span: Span) {
+        if !self.known_variables.contains(&name.to_string()) {
+            self.found.push(name.to_string());
+        }
+    }
+
+    fn visit_block(&mut self, block: &Arc<Block>) {
+        self.found
+            .extend(block.get_free_variables(self.known_variables));
+    }
+}
+
+impl<'a> Visitor for FreeVariableCollector<'a> {}
+
+/// Reference [`Visitor`] that collects every span a walk passes through a
+/// variable reference or a present flag — the two leaf occurrences this
+/// layer's hooks carry a `Span` for. Useful for a linter or highlighter that
+/// wants "every relevant span under this subtree" without re-implementing
+/// the recursion itself.
+#[derive(Default)]
+pub struct SpanCollector {
+    pub spans: Vec<Span>,
+}
+
+impl ExpressionVisitor for SpanCollector {
+    fn visit_variable(&mut self, _name: &str, span: Span) {
+        self.spans.push(span);
+    }
+}
+
+impl Visitor for SpanCollector {
+    fn visit_flag(&mut self, _name: &str, span: Span) {
+        self.spans.push(span);
     }
 }
 
@@ -1274,26 +2558,24 @@ pub enum NamedValue {
 }
 
 impl NamedValue {
-    fn has_var_usage(&self, var_name: &str) -> bool {
-        if let NamedValue::Value(_, se) = self {
-            se.has_var_usage(var_name)
-        } else {
-            false
-        }
-    }
-    pub fn get_free_variables(&self, known_variables: &mut Vec<String>) -> Vec<String> {
-        if let NamedValue::Value(_, se) = self {
-            se.get_free_variables(known_variables)
-        } else {
-            vec![]
-        }
-    }
     pub fn get_contents(&self) -> Option<&SpannedExpression> {
         match self {
             NamedValue::Value(_, expr) => Some(expr),
             _ => None,
         }
     }
+
+    /// The span of the flag occurrence this value came from, if it was
+    /// actually supplied on the command line. `AbsentSwitch`/`AbsentValue`
+    /// are placeholders from `Call::set_initial_flags` and carry no span.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            NamedValue::PresentSwitch(span) => Some(*span),
+            NamedValue::Value(span, _) => Some(*span),
+            NamedValue::AbsentSwitch | NamedValue::AbsentValue => None,
+        }
+    }
+
 }
 
 impl PrettyDebugWithSource for NamedValue {
@@ -1371,34 +2653,42 @@ impl Call {
     }
 
     pub fn has_var_usage(&self, var_name: &str) -> bool {
-        self.head.has_var_usage(var_name)
-            || (if let Some(pos) = &self.positional {
-                pos.iter().any(|x| x.has_var_usage(var_name))
-            } else {
-                false
-            })
-            || (if let Some(named) = &self.named {
-                named.has_var_usage(var_name)
-            } else {
-                false
-            })
-    }
+        struct VarUsage<'a> {
+            var_name: &'a str,
+            found: bool,
+        }
 
-    pub fn get_free_variables(&self, known_variables: &mut Vec<String>) -> Vec<String> {
-        let mut free_variables = vec![];
+        impl<'a> ExpressionVisitor for VarUsage<'a> {
+            fn visit_variable(&mut self, name: &str, _span: Span) {
+                self.found |= name == self.var_name;
+            }
 
-        free_variables.extend(self.head.get_free_variables(known_variables));
-        if let Some(pos) = &self.positional {
-            for pos in pos {
-                free_variables.extend(pos.get_free_variables(known_variables));
+            fn visit_block(&mut self, block: &Arc<Block>) {
+                self.found |= block.has_var_usage(self.var_name);
             }
         }
 
-        if let Some(named) = &self.named {
-            free_variables.extend(named.get_free_variables(known_variables));
-        }
+        impl<'a> Visitor for VarUsage<'a> {}
 
-        free_variables
+        let mut visitor = VarUsage {
+            var_name,
+            found: false,
+        };
+        visitor.visit_call(self);
+        visitor.found
+    }
+
+    pub fn get_free_variables(&self, known_variables: &mut Vec<String>) -> Vec<String> {
+        let mut visitor = FreeVariableCollector {
+            known_variables,
+            found: vec![],
+        };
+        visitor.visit_call(self);
+        visitor.found
+    }
+
+    pub fn substitute_all(&self, bindings: &HashMap<String, SpannedExpression>) -> Call {
+        SubstituteFolder { bindings }.fold_call(self)
     }
 }
 
@@ -1476,6 +2766,7 @@ pub enum FlatShape {
     GlobPattern,
     Identifier,
     Int,
+    InterpolationDelimiter,
     InternalCommand,
     ItVariable,
     Keyword,
@@ -1494,9 +2785,218 @@ pub enum FlatShape {
     Word,
 }
 
+/// The LSP `SemanticTokenTypes` legend entries this crate's highlighter
+/// actually produces. An editor assigns each variant a legend index when it
+/// registers the language server's semantic tokens capability; the variant
+/// order here carries no meaning on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SemanticTokenType {
+    Function,
+    Parameter,
+    Variable,
+    Operator,
+    String,
+    Number,
+    Keyword,
+    Comment,
+    Type,
+    Namespace,
+}
+
+/// One LSP semantic token, already delta-encoded the way
+/// `textDocument/semanticTokens` expects: `delta_line`/`delta_start` are
+/// relative to the *previous* token, not absolute. `token_modifiers` is the
+/// protocol's bitset; this crate doesn't have a modifiers table yet, so it's
+/// always `0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub delta_line: u32,
+    pub delta_start: u32,
+    pub length: u32,
+    pub token_type: SemanticTokenType,
+    pub token_modifiers: u32,
+}
+
+/// The crate's one fixed mapping from terminal-highlighting shape to LSP
+/// semantic token type, kept in a single place so the two consumers of
+/// `FlatShape` — the terminal highlighter and an LSP server — agree on what
+/// each span means. Shapes that exist purely for terminal styling
+/// (delimiters, whitespace, separators, bare words) have no LSP analogue and
+/// map to `None`, meaning no token is emitted for that span.
+fn semantic_token_type(shape: &FlatShape) -> Option<SemanticTokenType> {
+    match shape {
+        FlatShape::InternalCommand | FlatShape::ExternalCommand => {
+            Some(SemanticTokenType::Function)
+        }
+        FlatShape::Flag | FlatShape::ShorthandFlag => Some(SemanticTokenType::Parameter),
+        FlatShape::Variable | FlatShape::ItVariable => Some(SemanticTokenType::Variable),
+        FlatShape::Operator
+        | FlatShape::Pipe
+        | FlatShape::Dot
+        | FlatShape::DotDot
+        | FlatShape::DotDotLeftAngleBracket => Some(SemanticTokenType::Operator),
+        FlatShape::String | FlatShape::StringMember => Some(SemanticTokenType::String),
+        FlatShape::Int | FlatShape::Decimal | FlatShape::Size { .. } => {
+            Some(SemanticTokenType::Number)
+        }
+        FlatShape::Keyword => Some(SemanticTokenType::Keyword),
+        FlatShape::Comment => Some(SemanticTokenType::Comment),
+        FlatShape::Type => Some(SemanticTokenType::Type),
+        FlatShape::Path | FlatShape::GlobPattern => Some(SemanticTokenType::Namespace),
+        FlatShape::BareMember
+        | FlatShape::CloseDelimiter(_)
+        | FlatShape::ExternalWord
+        | FlatShape::Garbage
+        | FlatShape::Identifier
+        | FlatShape::InterpolationDelimiter
+        | FlatShape::OpenDelimiter(_)
+        | FlatShape::Separator
+        | FlatShape::Whitespace
+        | FlatShape::Word => None,
+    }
+}
+
+/// Turn a flattened, already-highlighted shape stream into the delta-encoded
+/// token list `textDocument/semanticTokens/full` returns. `Size` is the one
+/// structured shape — its `number`/`unit` spans are split into two adjacent
+/// number tokens rather than one token spanning the gap between them, so
+/// `5kb` highlights as `5` + `kb` the same way the terminal highlighter
+/// colors it in two pieces.
+///
+/// Columns are byte offsets into `source`, not UTF-16 code units — an LSP
+/// server sitting in front of this still needs to do that conversion for
+/// non-ASCII lines, since nothing in this crate tracks encoding today.
+pub fn to_semantic_tokens(shapes: &[Spanned<FlatShape>], source: &str) -> Vec<SemanticToken> {
+    let line_starts = line_start_offsets(source);
+    let mut tokens = Vec::new();
+    let mut prev_line = 0u32;
+    let mut prev_start = 0u32;
+
+    for shape in shapes {
+        let token_type = match semantic_token_type(&shape.item) {
+            Some(token_type) => token_type,
+            None => continue,
+        };
+
+        let spans: &[Span] = match &shape.item {
+            FlatShape::Size { number, unit } => &[*number, *unit],
+            _ => std::slice::from_ref(&shape.span),
+        };
+
+        for span in spans {
+            let (line, start) = line_col(&line_starts, span.start);
+            let length = (span.end - span.start) as u32;
+
+            let (delta_line, delta_start) = if line == prev_line {
+                (0, start.saturating_sub(prev_start))
+            } else {
+                (line - prev_line, start)
+            };
+
+            tokens.push(SemanticToken {
+                delta_line,
+                delta_start,
+                length,
+                token_type,
+                token_modifiers: 0,
+            });
+
+            prev_line = line;
+            prev_start = start;
+        }
+    }
+
+    tokens
+}
+
+/// Byte offset where each line begins (`line_starts[0]` is always `0`), used
+/// to turn `FlatShape`'s absolute byte spans into the `(line, column)` pairs
+/// semantic tokens are delta-encoded from.
+fn line_start_offsets(source: &str) -> Vec<usize> {
+    let mut line_starts = vec![0];
+    for (i, c) in source.char_indices() {
+        if c == '\n' {
+            line_starts.push(i + 1);
+        }
+    }
+    line_starts
+}
+
+fn line_col(line_starts: &[usize], offset: usize) -> (u32, u32) {
+    let line = match line_starts.binary_search(&offset) {
+        Ok(line) => line,
+        Err(next) => next - 1,
+    };
+    (line as u32, (offset - line_starts[line]) as u32)
+}
+
+/// Two flags belonging to the same exclusive group were both supplied;
+/// `first` and `second` point at each occurrence so the caller can report
+/// both sides of the ambiguity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagConflict {
+    pub first: Span,
+    pub second: Span,
+}
+
+/// Declares relationships between named flags that a flat `IndexMap` can't
+/// express: aliases (a long name and a canonical short name that resolve
+/// to the same slot) and exclusive groups (flags that conflict with each
+/// other, like `--ascending` and `--descending`).
+///
+/// `NamedArguments` consults this declaration so that `insert_switch`,
+/// `insert_optional` and `insert_mandatory` record a conflict instead of
+/// silently overwriting an existing entry when a second member of an
+/// exclusive group is supplied.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagGroups {
+    aliases: IndexMap<String, String>,
+    groups: IndexMap<String, usize>,
+    next_group: usize,
+}
+
+impl FlagGroups {
+    pub fn new() -> FlagGroups {
+        Default::default()
+    }
+
+    /// Register `alias` as another name for `canonical`; inserting or
+    /// resolving either name operates on the same slot.
+    pub fn add_alias(&mut self, canonical: impl Into<String>, alias: impl Into<String>) {
+        self.aliases.insert(alias.into(), canonical.into());
+    }
+
+    /// Declare `names` as mutually exclusive: supplying more than one of
+    /// them in the same call is a conflict.
+    pub fn add_exclusive_group<I, S>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let group = self.next_group;
+        self.next_group += 1;
+
+        for name in names {
+            self.groups.insert(name.into(), group);
+        }
+    }
+
+    /// The canonical name `name` resolves to through its alias, or `name`
+    /// itself if it isn't an alias.
+    fn canonical<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    fn group_of(&self, name: &str) -> Option<usize> {
+        self.groups.get(name).copied()
+    }
+}
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NamedArguments {
     pub named: IndexMap<String, NamedValue>,
+    groups: FlagGroups,
+    conflicts: Vec<FlagConflict>,
 }
 
 #[allow(clippy::derive_hash_xor_eq)]
@@ -1554,7 +3054,7 @@ impl NamedArguments {
     }
 
     pub fn get(&self, name: &str) -> Option<&NamedValue> {
-        self.named.get(name)
+        self.named.get(self.groups.canonical(name))
     }
 
     pub fn is_empty(&self) -> bool {
@@ -1562,29 +3062,122 @@ impl NamedArguments {
     }
 
     pub fn has_var_usage(&self, var_name: &str) -> bool {
-        self.iter().any(|x| x.1.has_var_usage(var_name))
+        struct VarUsage<'a> {
+            var_name: &'a str,
+            found: bool,
+        }
+
+        impl<'a> ExpressionVisitor for VarUsage<'a> {
+            fn visit_variable(&mut self, name: &str, _span: Span) {
+                self.found |= name == self.var_name;
+            }
+
+            fn visit_block(&mut self, block: &Arc<Block>) {
+                self.found |= block.has_var_usage(self.var_name);
+            }
+        }
+
+        impl<'a> Visitor for VarUsage<'a> {}
+
+        let mut visitor = VarUsage {
+            var_name,
+            found: false,
+        };
+        visitor.visit_named_arguments(self);
+        visitor.found
     }
 
     pub fn get_free_variables(&self, known_variables: &mut Vec<String>) -> Vec<String> {
-        let mut free_variables = vec![];
-        for (_, val) in &self.named {
-            free_variables.extend(val.get_free_variables(known_variables));
-        }
-        free_variables
+        let mut visitor = FreeVariableCollector {
+            known_variables,
+            found: vec![],
+        };
+        visitor.visit_named_arguments(self);
+        visitor.found
+    }
+
+    pub fn substitute_all(&self, bindings: &HashMap<String, SpannedExpression>) -> NamedArguments {
+        SubstituteFolder { bindings }.fold_named_arguments(self)
+    }
+
+    /// Look up `name`, following aliases declared with `declare_alias` to
+    /// the canonical slot they resolve to. `get` does the same resolution;
+    /// `resolve` is the alias-explicit name command implementations and the
+    /// highlighter are expected to reach for.
+    pub fn resolve(&self, name: &str) -> Option<&NamedValue> {
+        self.get(name)
     }
 }
 
 impl NamedArguments {
+    /// Register `alias` as another name for `canonical`; see
+    /// `FlagGroups::add_alias`.
+    pub fn declare_alias(&mut self, canonical: impl Into<String>, alias: impl Into<String>) {
+        self.groups.add_alias(canonical, alias);
+    }
+
+    /// Declare `names` as mutually exclusive; see
+    /// `FlagGroups::add_exclusive_group`.
+    pub fn declare_exclusive_group<I, S>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.groups.add_exclusive_group(names);
+    }
+
+    /// Conflicts recorded by `insert_switch`/`insert_optional`/
+    /// `insert_mandatory` because a second member of an exclusive group
+    /// was supplied after the first.
+    pub fn conflicts(&self) -> Vec<(Span, Span)> {
+        self.conflicts
+            .iter()
+            .map(|conflict| (conflict.first, conflict.second))
+            .collect()
+    }
+
+    /// Insert `value` under the canonical name `name` resolves to, unless
+    /// it conflicts with another member of its exclusive group that's
+    /// already present, in which case the conflict is recorded instead and
+    /// the existing entry is left untouched.
+    fn insert_resolved(&mut self, name: String, value: NamedValue) {
+        let canonical = self.groups.canonical(&name).to_string();
+
+        if let Some(new_span) = value.span() {
+            if let Some(group) = self.groups.group_of(&canonical) {
+                let mut existing = None;
+                for (other, other_value) in self.named.iter() {
+                    if other != &canonical && self.groups.group_of(other) == Some(group) {
+                        if let Some(span) = other_value.span() {
+                            existing = Some(span);
+                            break;
+                        }
+                    }
+                }
+
+                if let Some(existing_span) = existing {
+                    self.conflicts.push(FlagConflict {
+                        first: existing_span,
+                        second: new_span,
+                    });
+                    return;
+                }
+            }
+        }
+
+        self.named.insert(canonical, value);
+    }
+
     pub fn insert_switch(&mut self, name: impl Into<String>, switch: Option<Flag>) {
         let name = name.into();
         trace!("Inserting switch -- {} = {:?}", name, switch);
 
-        match switch {
-            None => self.named.insert(name, NamedValue::AbsentSwitch),
-            Some(flag) => self
-                .named
-                .insert(name, NamedValue::PresentSwitch(flag.name)),
+        let value = match switch {
+            None => NamedValue::AbsentSwitch,
+            Some(flag) => NamedValue::PresentSwitch(flag.name),
         };
+
+        self.insert_resolved(name, value);
     }
 
     pub fn insert_optional(
@@ -1593,12 +3186,12 @@ impl NamedArguments {
         flag_span: Span,
         expr: Option<SpannedExpression>,
     ) {
-        match expr {
-            None => self.named.insert(name.into(), NamedValue::AbsentValue),
-            Some(expr) => self
-                .named
-                .insert(name.into(), NamedValue::Value(flag_span, Box::new(expr))),
+        let value = match expr {
+            None => NamedValue::AbsentValue,
+            Some(expr) => NamedValue::Value(flag_span, Box::new(expr)),
         };
+
+        self.insert_resolved(name.into(), value);
     }
 
     pub fn insert_mandatory(
@@ -1607,15 +3200,16 @@ impl NamedArguments {
         flag_span: Span,
         expr: SpannedExpression,
     ) {
-        self.named
-            .insert(name.into(), NamedValue::Value(flag_span, Box::new(expr)));
+        self.insert_resolved(name.into(), NamedValue::Value(flag_span, Box::new(expr)));
     }
 
     pub fn switch_present(&self, switch: &str) -> bool {
-        self.named
-            .get(switch)
-            .map(|t| matches!(t, NamedValue::PresentSwitch(_)))
-            .unwrap_or(false)
+        // A keyed lookup, not a tree walk: the `Visitor` framework is for
+        // cases that need structural recursion (expressions, blocks), and
+        // using it here for what `get` already does in O(1) was a needless
+        // regression on a hot path. `get` already resolves aliases to their
+        // canonical name.
+        matches!(self.get(switch), Some(NamedValue::PresentSwitch(_)))
     }
 }
 
@@ -1684,3 +3278,121 @@ impl Flag {
         }
     }
 }
+
+/// One piece of a `$"..."` interpolated string after splitting on `{...}`
+/// argument boundaries. Every span refers only to the piece's own content —
+/// an `Argument`'s span is what's between its braces, not the braces
+/// themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpolationPiece {
+    /// A run of literal text, `{{`/`}}` escapes included verbatim (it's up
+    /// to the renderer reading this span to fold an escape down to a single
+    /// brace; the scan only needs to know it isn't an argument boundary).
+    Literal(Span),
+    /// A balanced `{ ... }` argument expression — just the span of its
+    /// contents, not yet parsed into an `Expression`.
+    Argument(Span),
+    /// An unmatched `{`/`}`, or an empty `{}`: not valid interpolation
+    /// syntax.
+    Garbage(Span),
+}
+
+/// Scan an interpolated string's contents (the text between the opening and
+/// closing `"` of `$"..."`, not the quotes themselves) left-to-right into an
+/// alternating sequence of literal-text and `{...}` argument pieces — the
+/// same style of scan `format!`'s own parser uses for `{}` placeholders.
+/// `base` is the absolute byte offset `fragment` starts at in the original
+/// source, so every piece's span is absolute rather than relative to the
+/// fragment.
+pub fn tokenize_interpolation(fragment: &str, base: usize) -> Vec<InterpolationPiece> {
+    let bytes = fragment.as_bytes();
+    let mut pieces = vec![];
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'{' if bytes.get(i + 1) == Some(&b'{') => i += 2,
+            b'}' if bytes.get(i + 1) == Some(&b'}') => i += 2,
+            b'{' => {
+                if literal_start < i {
+                    pieces.push(InterpolationPiece::Literal(Span::new(
+                        base + literal_start,
+                        base + i,
+                    )));
+                }
+
+                let open = i;
+                i += 1;
+                let mut depth = 1;
+                while i < bytes.len() && depth > 0 {
+                    match bytes[i] {
+                        b'{' => depth += 1,
+                        b'}' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+
+                pieces.push(if depth > 0 {
+                    // Never closed: the rest of the fragment is garbage.
+                    InterpolationPiece::Garbage(Span::new(base + open, base + i))
+                } else if i - open == 2 {
+                    // `{}`: an empty argument, not a piece worth parsing.
+                    InterpolationPiece::Garbage(Span::new(base + open, base + i))
+                } else {
+                    InterpolationPiece::Argument(Span::new(base + open + 1, base + i - 1))
+                });
+
+                literal_start = i;
+            }
+            b'}' => {
+                // An unmatched close brace with no opener before it.
+                pieces.push(InterpolationPiece::Garbage(Span::new(
+                    base + i,
+                    base + i + 1,
+                )));
+                i += 1;
+                literal_start = i;
+            }
+            _ => i += 1,
+        }
+    }
+
+    if literal_start < bytes.len() {
+        pieces.push(InterpolationPiece::Literal(Span::new(
+            base + literal_start,
+            base + bytes.len(),
+        )));
+    }
+
+    pieces
+}
+
+/// Turn a scanned interpolation into the shapes this crate's own highlighter
+/// can emit directly: `InterpolationDelimiter` for each brace, `String` for
+/// literal runs, and `Garbage` over an unmatched brace or empty `{}`. The
+/// expression inside an `Argument` piece isn't flattened here — lowering a
+/// full expression tree into `FlatShape`s is the parser's job, not `hir`'s —
+/// so the argument spans are also returned for the caller to re-enter its
+/// own flattener on.
+pub fn interpolation_shapes(pieces: &[InterpolationPiece]) -> (Vec<Spanned<FlatShape>>, Vec<Span>) {
+    let mut shapes = vec![];
+    let mut arguments = vec![];
+
+    for piece in pieces {
+        match piece {
+            InterpolationPiece::Literal(span) => shapes.push(FlatShape::String.spanned(*span)),
+            InterpolationPiece::Argument(span) => {
+                let open = Span::new(span.start - 1, span.start);
+                let close = Span::new(span.end, span.end + 1);
+                shapes.push(FlatShape::InterpolationDelimiter.spanned(open));
+                shapes.push(FlatShape::InterpolationDelimiter.spanned(close));
+                arguments.push(*span);
+            }
+            InterpolationPiece::Garbage(span) => shapes.push(FlatShape::Garbage.spanned(*span)),
+        }
+    }
+
+    (shapes, arguments)
+}